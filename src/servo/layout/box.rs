@@ -9,7 +9,7 @@ use core::dvec::DVec;
 use core::to_str::ToStr;
 use core::rand;
 use css::styles::SpecifiedStyle;
-use css::values::{BoxSizing, Length, Px, CSSDisplay, Specified, BgColor, BgColorTransparent, BdrColor, PosAbsolute};
+use css::values::{BoxSizing, BoxShadow, CSSValue, Length, Auto, Px, CSSDisplay, Specified, BgColor, BgColorTransparent, BdrColor, PosAbsolute, PosFixed, VATop, VABottom, VAMiddle, VATextTop, VATextBottom, VALength};
 use dl = gfx::display_list;
 use dom::element::{ElementKind, HTMLDivElement, HTMLImageElement};
 use dom::node::{Element, Node, NodeData, NodeKind, NodeTree};
@@ -60,6 +60,262 @@ padding, backgrounds. It is analogous to a CSS nonreplaced content box.
 
 */
 
+/* The widths of the four edges of a box's margin, border, or padding,
+   as resolved lengths. Used to inflate a box's content rect into its
+   border box or margin box, per CSS 2.1 Section 8.1. */
+pub struct EdgeSizes {
+    top : au,
+    right : au,
+    bottom : au,
+    left : au
+}
+
+fn EdgeSizes(top: au, right: au, bottom: au, left: au) -> EdgeSizes {
+    EdgeSizes { top: top, right: right, bottom: bottom, left: left }
+}
+
+fn zero_edge_sizes() -> EdgeSizes {
+    EdgeSizes(au(0), au(0), au(0), au(0))
+}
+
+/* Resolves a CSS length to an au, per CSS 2.1 Section 4.3.2. Percentage
+   widths are deferred to the owning flow, which knows the containing
+   block width; they resolve to zero here. */
+fn resolve_length(value: CSSValue<Length>) -> au {
+    match value {
+        Specified(Px(px)) => au::from_frac_px(px),
+        _ => au(0)
+    }
+}
+
+/* Like `resolve_length`, but treats `auto` as zero fringe; the flow is
+   responsible for distributing auto margins. */
+fn resolve_margin(value: CSSValue<Length>) -> au {
+    match value {
+        Specified(Px(px)) => au::from_frac_px(px),
+        Specified(Auto) | _ => au(0)
+    }
+}
+
+/* Resolves a CSS offset or dimension (`left`, `width`, etc.) to an au,
+   leaving it unresolved (`None`) when `auto` or otherwise unspecified;
+   callers fall back to the static position or flow-assigned size. */
+fn resolve_offset(value: CSSValue<Length>) -> Option<au> {
+    match value {
+        Specified(Px(px)) => Some(au::from_frac_px(px)),
+        _ => None
+    }
+}
+
+/* The intrinsic (pre-CSS) size of a replaced element's content, in
+   pixels: the decoded bitmap's size if it's ready, else the size
+   declared by the image's metadata (available well before the full
+   bitmap is decoded), else unknown. */
+fn intrinsic_image_size(i: &ImageHolder) -> Option<Size2D<int>> {
+    match i.get_size() {
+        Some(copy size) => Some(size),
+        None => i.get_size_from_metadata()
+    }
+}
+
+/* Resolves a replaced box's (e.g. ImageBox's) content size per CSS 2.1
+   Section 10.3.2: CSS `width`/`height` win outright; if only one is
+   specified, the other is scaled by the image's intrinsic ratio; if
+   neither is specified, the intrinsic size is used directly; and if
+   nothing is known yet (no CSS size, no metadata, no bitmap), the size
+   is zero and build_display_list paints a placeholder instead. */
+fn resolve_replaced_dimensions(style: &SpecifiedStyle, i: &ImageHolder) -> Size2D<au> {
+    let css_width  = match style.width  { Specified(Px(px)) => Some(px), _ => None };
+    let css_height = match style.height { Specified(Px(px)) => Some(px), _ => None };
+    let intrinsic = intrinsic_image_size(i);
+
+    let (width_px, height_px) : (float, float) = match (css_width, css_height) {
+        (Some(w), Some(h)) => (w, h),
+        (Some(w), None) => {
+            let h = match intrinsic {
+                Some(copy size) if size.width != 0 => w * (size.height as float) / (size.width as float),
+                _ => w
+            };
+            (w, h)
+        }
+        (None, Some(h)) => {
+            let w = match intrinsic {
+                Some(copy size) if size.height != 0 => h * (size.width as float) / (size.height as float),
+                _ => h
+            };
+            (w, h)
+        }
+        (None, None) => {
+            match intrinsic {
+                Some(copy size) => (size.width as float, size.height as float),
+                None => (0.0, 0.0)
+            }
+        }
+    };
+
+    Size2D(au::from_frac_px(width_px), au::from_frac_px(height_px))
+}
+
+/* A Gaussian blur's visible tail extends well past its nominal radius;
+   inflating the shadow's bounding rect by this many times the blur
+   radius (in addition to the spread) keeps both the dirty-rect test
+   and the painting backend covering the whole visible blur. */
+static BLUR_INFLATION_FACTOR: int = 3;
+
+fn inflate_for_shadow(spread: au, blur: au) -> au {
+    let mut inflation = spread;
+    for int::range(0, BLUR_INFLATION_FACTOR) |_| {
+        inflation += blur;
+    }
+    inflation
+}
+
+/* Inflates `rect` outward by the sum of the two given edge sizes on
+   each side. Used to grow a content box into a border box, and a
+   border box into a margin box. */
+pure fn inflate_rect(rect: &Rect<au>, a: &EdgeSizes, b: &EdgeSizes) -> Rect<au> {
+    Rect {
+        origin: Point2D(rect.origin.x - (a.left + b.left),
+                         rect.origin.y - (a.top + b.top)),
+        size: Size2D(rect.size.width + a.left + a.right + b.left + b.right,
+                      rect.size.height + a.top + a.bottom + b.top + b.bottom)
+    }
+}
+
+/* The containing block against which an absolutely- or fixed-positioned
+   box resolves its offsets and dimensions, per CSS 2.1 Section 10.1.
+   For `position: absolute` this is the padding box of the nearest
+   positioned ancestor; for `position: fixed` it is the viewport, and
+   `is_fixed` is set so that display-list construction can keep the box
+   anchored to the viewport rather than the scrolled document. */
+pub struct ContainingBlock {
+    rect : Rect<au>,
+    is_fixed : bool
+}
+
+fn ContainingBlock(rect: Rect<au>, is_fixed: bool) -> ContainingBlock {
+    ContainingBlock { rect: rect, is_fixed: is_fixed }
+}
+
+/* A box's vertical metrics within an inline formatting context: how far
+   it extends above (`ascent`) and below (`descent`) its own baseline,
+   and the total height it contributes to its line box. The inline flow
+   uses these to size each line box and to position every box on it via
+   `vertical-align` (see `resolve_vertical_align`). */
+pub struct InlineMetrics {
+    ascent : au,
+    descent : au,
+    line_height : au
+}
+
+fn InlineMetrics(ascent: au, descent: au, line_height: au) -> InlineMetrics {
+    InlineMetrics { ascent: ascent, descent: descent, line_height: line_height }
+}
+
+/* A child stacking context, ordered against its siblings by its
+   `z-index` (CSS 2.1 Appendix E). Stored in `StackingContext`'s
+   `negative_children`/`positive_children` lists, which are kept sorted
+   by z-index as children are added, so that boxes with equal z-index
+   stay in the document order they were routed in (a stable sort). */
+struct ZChild {
+    z_index : int,
+    list : @dl::DisplayList
+}
+
+fn ZChild(z_index: int, list: @dl::DisplayList) -> ZChild {
+    ZChild { z_index: z_index, list: list }
+}
+
+fn insert_zchild_sorted(dvec: &DVec<ZChild>, child: ZChild) {
+    dvec.push(child);
+    let mut j = dvec.len() - 1;
+    while j > 0 && dvec.get_elt(j - 1).z_index > dvec.get_elt(j).z_index {
+        let a = dvec.get_elt(j - 1);
+        let b = dvec.get_elt(j);
+        dvec.set_elt(j - 1, b);
+        dvec.set_elt(j, a);
+        j -= 1;
+    }
+}
+
+/* The ordered set of display-list layers that make up one CSS 2.1
+   Section 9.9.1 stacking context, in CSS 2.1 Appendix E painting order:
+   negative z-index children, this context's own block-level
+   backgrounds/borders, non-positioned floats, in-flow inline content,
+   z-index:auto positioned descendants, then positive z-index children.
+   Every box routes its display items into exactly one of these layers
+   (see `RenderBox::target_display_list`); `flatten()` returns them in
+   the order they should be painted. */
+pub struct StackingContext {
+    negative_children : DVec<ZChild>,
+    block_backgrounds_and_borders : @dl::DisplayList,
+    floats : @dl::DisplayList,
+    inline_content : @dl::DisplayList,
+    positioned_descendants : @dl::DisplayList,
+    positive_children : DVec<ZChild>
+}
+
+fn StackingContext() -> StackingContext {
+    StackingContext {
+        negative_children: DVec(),
+        block_backgrounds_and_borders: @dl::DisplayList(),
+        floats: @dl::DisplayList(),
+        inline_content: @dl::DisplayList(),
+        positioned_descendants: @dl::DisplayList(),
+        positive_children: DVec()
+    }
+}
+
+impl StackingContext {
+    fn flatten(&self) -> ~[@dl::DisplayList] {
+        let mut result : ~[@dl::DisplayList] = ~[];
+
+        for self.negative_children.each |child| { result.push(child.list); }
+
+        result.push(self.block_backgrounds_and_borders);
+        result.push(self.floats);
+        result.push(self.inline_content);
+        result.push(self.positioned_descendants);
+
+        for self.positive_children.each |child| { result.push(child.list); }
+
+        result
+    }
+}
+
+/* Resolves CSS `vertical-align` (CSS 2.1 Section 10.8.1) to an offset
+   applied to a box's top, relative to the top of its line box. The line
+   box's own ascent/descent (the max over all boxes on it) bound
+   `baseline`/`top`/`bottom`/`middle`; `text-top`/`text-bottom` align to
+   the edges of the line's dominant font instead, which the inline flow
+   supplies as `font_ascent`/`font_descent`. A positive length raises
+   the box above the baseline, per the property's definition; percentage
+   offsets are treated as zero (see below). */
+fn resolve_vertical_align(style: &SpecifiedStyle, metrics: &InlineMetrics,
+                           line_ascent: au, line_descent: au,
+                           font_ascent: au, font_descent: au) -> au {
+    match style.vertical_align {
+        // top: align this box's top with the line box's top.
+        Specified(VATop) => au(0),
+        Specified(VABottom) => (line_ascent + line_descent) - metrics.line_height,
+        Specified(VAMiddle) => {
+            let line_middle = (line_ascent - line_descent) / au(2);
+            let box_middle = (metrics.ascent - metrics.descent) / au(2);
+            line_ascent - line_middle - metrics.ascent + box_middle
+        }
+        Specified(VATextTop) => line_ascent - font_ascent,
+        Specified(VATextBottom) => (line_ascent + font_descent) - metrics.line_height,
+        Specified(VALength(Px(px))) => line_ascent - metrics.ascent - au::from_frac_px(px),
+        // TODO: percentage vertical-align offsets (resolved against
+        // line-height, per CSS 2.1 Section 10.8.1) aren't implemented
+        // yet; treat as a zero offset, as resolve_length does for
+        // percentage widths/margins elsewhere in this file.
+        Specified(VALength(_)) => line_ascent - metrics.ascent,
+        // baseline: align this box's baseline with the line's baseline.
+        _ => line_ascent - metrics.ascent
+    }
+}
+
 /* A box's kind influences how its styles are interpreted during
    layout.  For example, replaced content such as images are resized
    differently than tables, text, or other content.
@@ -75,6 +331,21 @@ struct RenderBoxData {
     /* position of this box relative to owning flow */
     mut position : Rect<au>,
     font_size : Length,
+    /* resolved margin, border, and padding widths. Computed from the
+       node's SpecifiedStyle during layout, before display list
+       construction consults border_box()/margin_box(). */
+    mut margin : EdgeSizes,
+    mut border : EdgeSizes,
+    mut padding : EdgeSizes,
+    /* Where this box would have been laid out had `position` been
+       `static`, per CSS 2.1 Section 10.3.7. Used as the fallback
+       offset for absolutely-positioned boxes that specify neither
+       `left`/`right` nor `top`/`bottom`. */
+    mut static_position : Point2D<au>,
+    /* The containing block established by the nearest positioned
+       ancestor (or the viewport, for `fixed`), threaded down through
+       layout. `None` until a positioned ancestor assigns it. */
+    mut containing_block : Option<ContainingBlock>,
     /* TODO (Issue #87): debug only */
     mut id: int
 }
@@ -116,15 +387,24 @@ trait RenderBoxMethods {
     pure fn content_box() -> Rect<au>;
     pure fn border_box() -> Rect<au>;
     pure fn margin_box() -> Rect<au>;
+    fn set_static_position(Point2D<au>);
+    fn set_containing_block(ContainingBlock);
+    pure fn is_positioned() -> bool;
+    pure fn resolve_absolute_rect() -> Rect<au>;
+    pure fn establishes_stacking_context() -> bool;
 
     fn split_to_width(@self, &LayoutContext, au, starts_line: bool) -> SplitBoxResult;
     fn get_min_width(&LayoutContext) -> au;
     fn get_pref_width(&LayoutContext) -> au;
+    fn compute_box_model();
     fn get_used_width() -> (au, au);
     fn get_used_height() -> (au, au);
+    fn inline_metrics(&LayoutContext) -> InlineMetrics;
+    pure fn vertical_align_offset(metrics: &InlineMetrics, line_ascent: au, line_descent: au,
+                                   font_ascent: au, font_descent: au) -> au;
     fn create_inline_spacer_for_side(&LayoutContext, InlineSpacerSide) -> Option<@RenderBox>;
-    fn build_display_list(@self, &dl::DisplayListBuilder, dirty: &Rect<au>, 
-                          offset: &Point2D<au>, &dl::DisplayList);
+    fn build_display_list(@self, &dl::DisplayListBuilder, dirty: &Rect<au>,
+                          offset: &Point2D<au>, &StackingContext);
 }
 
 fn RenderBoxData(node: Node, ctx: @FlowContext, id: int) -> RenderBoxData {
@@ -133,6 +413,11 @@ fn RenderBoxData(node: Node, ctx: @FlowContext, id: int) -> RenderBoxData {
         mut ctx  : ctx,
         mut position : au::zero_rect(),
         font_size: Px(0.0),
+        mut margin : zero_edge_sizes(),
+        mut border : zero_edge_sizes(),
+        mut padding : zero_edge_sizes(),
+        mut static_position : Point2D(au(0), au(0)),
+        mut containing_block : None,
         id : id
     }
 }
@@ -267,9 +552,7 @@ impl RenderBox : RenderBoxMethods {
             // FlowContext will combine the width of this element and
             // that of its children to arrive at the context width.
             GenericBox(*) => au(0),
-            // TODO: consult CSS 'width', margin, border.
-            // TODO: If image isn't available, consult 'width'.
-            ImageBox(_,i) => au::from_px(i.get_size().get_default(Size2D(0,0)).width),
+            ImageBox(_,i) => resolve_replaced_dimensions(&self.d().node.style(), i).width,
             TextBox(_,d) => d.run.min_width_for_range(d.offset, d.length),
             UnscannedTextBox(*) => fail ~"Shouldn't see unscanned boxes here."
         }
@@ -283,7 +566,7 @@ impl RenderBox : RenderBoxMethods {
             // FlowContext will combine the width of this element and
             // that of its children to arrive at the context width.
             GenericBox(*) => au(0),
-            ImageBox(_,i) => au::from_px(i.get_size().get_default(Size2D(0,0)).width),
+            ImageBox(_,i) => resolve_replaced_dimensions(&self.d().node.style(), i).width,
 
             // a text box cannot span lines, so assume that this is an unsplit text box.
 
@@ -310,22 +593,85 @@ impl RenderBox : RenderBoxMethods {
         }
     }
 
+    /* Resolves this box's margin, border, and padding from the
+       owning node's SpecifiedStyle and stores them for later
+       consultation by border_box()/margin_box()/get_used_width()/
+       get_used_height(). Must run before those are called. */
+    fn compute_box_model() {
+        let style = self.d().node.style();
+        self.d().margin = EdgeSizes(resolve_margin(style.margin_top),
+                                     resolve_margin(style.margin_right),
+                                     resolve_margin(style.margin_bottom),
+                                     resolve_margin(style.margin_left));
+        self.d().border = EdgeSizes(resolve_length(style.border_top_width),
+                                     resolve_length(style.border_right_width),
+                                     resolve_length(style.border_bottom_width),
+                                     resolve_length(style.border_left_width));
+        self.d().padding = EdgeSizes(resolve_length(style.padding_top),
+                                      resolve_length(style.padding_right),
+                                      resolve_length(style.padding_bottom),
+                                      resolve_length(style.padding_left));
+    }
+
     /* Returns the amount of left, right "fringe" used by this
-    box. This should be based on margin, border, padding, width. */
+    box. This is based on margin, border, and padding; percentage
+    widths are left to the owning flow. */
     fn get_used_width() -> (au, au) {
-        // TODO: this should actually do some computation!
-        // See CSS 2.1, Section 10.3, 10.4.
+        let margin = self.d().margin;
+        let border = self.d().border;
+        let padding = self.d().padding;
 
-        (au(0), au(0))
+        (margin.left + border.left + padding.left,
+         margin.right + border.right + padding.right)
     }
-    
-    /* Returns the amount of left, right "fringe" used by this
-    box. This should be based on margin, border, padding, width. */
+
+    /* Returns the amount of top, bottom "fringe" used by this
+    box. This is based on margin, border, and padding; percentage
+    heights are left to the owning flow. */
     fn get_used_height() -> (au, au) {
-        // TODO: this should actually do some computation!
-        // See CSS 2.1, Section 10.5, 10.6.
+        let margin = self.d().margin;
+        let border = self.d().border;
+        let padding = self.d().padding;
 
-        (au(0), au(0))
+        (margin.top + border.top + padding.top,
+         margin.bottom + border.bottom + padding.bottom)
+    }
+
+    /* This box's ascent/descent/line-height within the inline flow that
+       contains it (CSS 2.1 Section 10.8). Images sit on the baseline by
+       default, so their whole border box counts as ascent; text takes
+       the ascent/descent of the run's font as a whole, not of the glyphs
+       in this box's range;
+       a childless GenericBox (e.g. an empty inline box) derives from its
+       own font. */
+    fn inline_metrics(ctx: &LayoutContext) -> InlineMetrics {
+        match self {
+            TextBox(_,d) => {
+                let metrics = d.run.font_metrics();
+                InlineMetrics(metrics.ascent, metrics.descent, metrics.ascent + metrics.descent)
+            }
+            ImageBox(*) => {
+                let height = self.border_box().size.height;
+                InlineMetrics(height, au(0), height)
+            }
+            GenericBox(_) => {
+                let metrics = ctx.font_cache.get_metrics(self.d().font_size);
+                InlineMetrics(metrics.ascent, metrics.descent, metrics.ascent + metrics.descent)
+            }
+            UnscannedTextBox(*) => fail ~"Shouldn't see unscanned boxes here."
+        }
+    }
+
+    /* This box's `vertical-align` offset (CSS 2.1 Section 10.8.1) within
+       the line box that contains it, applied to the box's top relative
+       to the top of the line box. `metrics` is this box's own
+       `inline_metrics`; the inline flow calls this once it has computed
+       the line's overall ascent/descent (the max over all boxes on it)
+       and its dominant font's ascent/descent. */
+    pure fn vertical_align_offset(metrics: &InlineMetrics, line_ascent: au, line_descent: au,
+                                   font_ascent: au, font_descent: au) -> au {
+        resolve_vertical_align(&self.d().node.style(), metrics,
+                                line_ascent, line_descent, font_ascent, font_descent)
     }
 
     /* Whether "spacer" boxes are needed to stand in for this DOM node */
@@ -336,29 +682,19 @@ impl RenderBox : RenderBoxMethods {
     /* The box formed by the content edge, as defined in CSS 2.1 Section 8.1.
        Coordinates are relative to the owning flow. */
     pure fn content_box() -> Rect<au> {
+        if self.is_positioned() {
+            return self.resolve_absolute_rect();
+        }
+
         match self {
             ImageBox(_,i) => {
-                let size = i.size();
                 Rect {
                     origin: copy self.d().position.origin,
-                    size:   Size2D(au::from_px(size.width),
-                                   au::from_px(size.height))
+                    size:   resolve_replaced_dimensions(&self.d().node.style(), i)
                 }
             },
             GenericBox(*) => {
                 copy self.d().position
-                /* FIXME: The following hits an ICE for whatever reason
-
-                let origin = self.d().position.origin;
-                let size   = self.d().position.size;
-                let (offset_left, offset_right) = self.get_used_width();
-                let (offset_top, offset_bottom) = self.get_used_height();
-
-                Rect {
-                    origin: Point2D(origin.x + offset_left, origin.y + offset_top),
-                    size:   Size2D(size.width - (offset_left + offset_right),
-                                   size.height - (offset_top + offset_bottom))
-                }*/
             },
             TextBox(*) => {
                 copy self.d().position
@@ -368,31 +704,145 @@ impl RenderBox : RenderBoxMethods {
     }
 
     /* The box formed by the border edge, as defined in CSS 2.1 Section 8.1.
-       Coordinates are relative to the owning flow. */
+       Coordinates are relative to the owning flow. Inflates the content
+       box by padding and border on each edge. */
     pure fn border_box() -> Rect<au> {
-        // TODO: actually compute content_box + padding + border
-        self.content_box()
+        let content = self.content_box();
+        let border = self.d().border;
+        let padding = self.d().padding;
+        inflate_rect(&content, &border, &padding)
     }
 
     /* The box fromed by the margin edge, as defined in CSS 2.1 Section 8.1.
-       Coordinates are relative to the owning flow. */
+       Coordinates are relative to the owning flow. Inflates the content
+       box by padding, border, and margin on each edge. */
     pure fn margin_box() -> Rect<au> {
-        // TODO: actually compute content_box + padding + border + margin
-        self.content_box()
+        let content = self.content_box();
+        let border = self.d().border;
+        let padding = self.d().padding;
+        let margin = self.d().margin;
+        let bordered = inflate_rect(&content, &border, &padding);
+        inflate_rect(&bordered, &margin, &zero_edge_sizes())
+    }
+
+    /* Records where this box would have been laid out had `position`
+       been `static`, per CSS 2.1 Section 10.3.7. The owning flow calls
+       this during normal layout, before it knows whether the box is
+       actually positioned. */
+    fn set_static_position(pos: Point2D<au>) {
+        self.d().static_position = pos;
+    }
+
+    /* Assigns the containing block this box resolves `left`/`right`/
+       `top`/`bottom`/`width`/`height` against: the padding box of the
+       nearest positioned ancestor, or the viewport for `fixed`. Threaded
+       down through layout by the flow that establishes it. */
+    fn set_containing_block(cb: ContainingBlock) {
+        self.d().containing_block = Some(cb);
+    }
+
+    pure fn is_positioned() -> bool {
+        match self.d().node.style().position {
+            Specified(PosAbsolute) | Specified(PosFixed) => true,
+            _ => false
+        }
+    }
+
+    /* Whether this box is the root of its own stacking context, per CSS
+       2.1 Section 9.9.1: a positioned box with a `z-index` other than
+       `auto`, or a box with `opacity` less than 1. */
+    pure fn establishes_stacking_context() -> bool {
+        let style = self.d().node.style();
+        let has_z_index = self.is_positioned() && match style.z_index {
+            Specified(_) => true,
+            _ => false
+        };
+        let has_opacity = match style.opacity {
+            Specified(opacity) => opacity < 1.0,
+            _ => false
+        };
+        has_z_index || has_opacity
+    }
+
+    /* Resolves the final rect of an absolutely- or fixed-positioned box
+       against its containing block, per CSS 2.1 Section 10.3.7 (width)
+       and 10.6.4 (height). Deferred until display-list construction,
+       since only then is the containing block's geometry fully settled.
+       `fixed` boxes reuse this path by supplying the viewport rect as
+       the containing block (see ContainingBlock::is_fixed). */
+    pure fn resolve_absolute_rect() -> Rect<au> {
+        let cb = match copy self.d().containing_block {
+            Some(cb) => cb,
+            // No positioned ancestor assigned a containing block yet;
+            // fall back to the box's static (in-flow) position.
+            None => return copy self.d().position
+        };
+
+        let style = self.d().node.style();
+        let left   = resolve_offset(style.left);
+        let right  = resolve_offset(style.right);
+        let top    = resolve_offset(style.top);
+        let bottom = resolve_offset(style.bottom);
+        let width  = resolve_offset(style.width);
+        let height = resolve_offset(style.height);
+
+        let resolved_width  = width.get_default(self.d().position.size.width);
+        let resolved_height = height.get_default(self.d().position.size.height);
+
+        let static_pos = self.d().static_position;
+
+        let x = match (left, right) {
+            (Some(l), _) => cb.rect.origin.x + l,
+            (None, Some(r)) => cb.rect.origin.x + cb.rect.size.width - r - resolved_width,
+            (None, None) => static_pos.x
+        };
+        let y = match (top, bottom) {
+            (Some(t), _) => cb.rect.origin.y + t,
+            (None, Some(b)) => cb.rect.origin.y + cb.rect.size.height - b - resolved_height,
+            (None, None) => static_pos.y
+        };
+
+        Rect { origin: Point2D(x, y), size: Size2D(resolved_width, resolved_height) }
     }
 
-    // TODO: implement this, generating spacer 
+    // TODO: implement this, generating spacer
     fn create_inline_spacer_for_side(_ctx: &LayoutContext, _side: InlineSpacerSide) -> Option<@RenderBox> {
         None
     }
 
-    // TODO: to implement stacking contexts correctly, we need to
-    // create a set of display lists, one per each layer of a stacking
-    // context. (CSS 2.1, Section 9.9.1). Each box is passed the list
-    // set representing the box's stacking context. When asked to
-    // construct its constituent display items, each box puts its
-    // DisplayItems into the correct stack layer (according to CSS 2.1
-    // Appendix E).  and then builder flattens the list at the end.
+    /* Chooses which of `stacking_context`'s ordered layers this box's
+       display items belong in (CSS 2.1 Section 9.9.1, Appendix E): a
+       box that establishes its own stacking context gets a fresh child
+       list registered under its z-index; an otherwise-positioned box
+       (z-index: auto) goes in `positioned_descendants`; inline-level
+       boxes go in `inline_content`; everything else (block-level
+       backgrounds/borders) goes in `block_backgrounds_and_borders`.
+       Floats aren't tracked as a distinct box kind yet, so `floats`
+       currently goes unused here; it exists so painting order is
+       already correct once they are. */
+    fn target_display_list(stacking_context: &StackingContext) -> @dl::DisplayList {
+        if self.establishes_stacking_context() {
+            let style = self.d().node.style();
+            let z_index = match style.z_index { Specified(z) => z, _ => 0 };
+            let child_list = @dl::DisplayList();
+            let child = ZChild(z_index, child_list);
+            if z_index < 0 {
+                insert_zchild_sorted(&stacking_context.negative_children, child);
+            } else {
+                insert_zchild_sorted(&stacking_context.positive_children, child);
+            }
+            return child_list;
+        }
+
+        if self.is_positioned() {
+            return stacking_context.positioned_descendants;
+        }
+
+        match self {
+            TextBox(*) | ImageBox(*) => stacking_context.inline_content,
+            _ => stacking_context.block_backgrounds_and_borders
+        }
+    }
 
     /* Methods for building a display list. This is a good candidate
        for a function pointer as the number of boxes explodes.
@@ -402,61 +852,103 @@ impl RenderBox : RenderBoxMethods {
     * `builder` - the display list builder which manages the coordinate system and options.
     * `dirty` - Dirty rectangle, in the coordinate system of the owning flow (self.ctx)
     * `origin` - Total offset from display list root flow to this box's owning flow
-    * `list` - List to which items should be appended
+    * `stacking_context` - The ordered set of display-list layers for this box's stacking
+      context; see `target_display_list`.
     */
     fn build_display_list(@self, builder: &dl::DisplayListBuilder, dirty: &Rect<au>,
-                          offset: &Point2D<au>, list: &dl::DisplayList) {
+                          offset: &Point2D<au>, stacking_context: &StackingContext) {
 
-        let style = self.d().node.style();
-        let box_bounds : Rect<au> = match style.position {
-            Specified(PosAbsolute) => {
-                let x_offset = match style.left {
-                    Specified(Px(px)) => au::from_frac_px(px),
-                    _ => self.d().position.origin.x
-                };
-                let y_offset = match style.top {
-                    Specified(Px(px)) => au::from_frac_px(px),
-                    _ => self.d().position.origin.y
-                };
-                Rect(Point2D(x_offset, y_offset), copy self.d().position.size)
-            }
-            _ => {
-                self.d().position
-            }
+        // Absolute/fixed boxes are resolved here, against the containing
+        // block threaded down through layout, rather than in the flow:
+        // the containing block's final geometry is only known now.
+        let box_bounds : Rect<au> = if self.is_positioned() {
+            self.resolve_absolute_rect()
+        } else {
+            self.d().position
         };
 
-        let abs_box_bounds = box_bounds.translate(offset);
-        debug!("RenderBox::build_display_list at rel=%?, abs=%?: %s", 
+        // A `fixed` box's containing block is the viewport (see
+        // ContainingBlock::is_fixed), so `box_bounds` is already
+        // viewport-relative; unlike in-flow and absolute boxes, it must
+        // not be re-translated by the ancestor offset, or it would drift
+        // with the scrolled document instead of staying anchored.
+        let is_fixed = match copy self.d().containing_block {
+            Some(cb) => cb.is_fixed,
+            None => false
+        };
+        let abs_box_bounds = if is_fixed { box_bounds } else { box_bounds.translate(offset) };
+        debug!("RenderBox::build_display_list at rel=%?, abs=%?: %s",
                box_bounds, abs_box_bounds, self.debug_str());
         debug!("RenderBox::build_display_list: dirty=%?, offset=%?", dirty, offset);
-        if abs_box_bounds.intersects(dirty) {
+
+        // A box-shadow can paint well outside the box's own bounds, so
+        // the dirty-rect test must consider the shadow's (inflated)
+        // bounds too, not just abs_box_bounds. Per shadow_bounds()'s
+        // contract, it's computed from the border box, not the content
+        // box, so it already includes the border/padding fringe.
+        let border_bounds = self.border_box();
+        let abs_border_bounds = if is_fixed { border_bounds } else { border_bounds.translate(offset) };
+        let shadow_bounds = self.shadow_bounds(&abs_border_bounds);
+        let test_bounds = match copy shadow_bounds {
+            Some(copy bounds) => abs_box_bounds.union(&bounds),
+            None => abs_box_bounds
+        };
+
+        if test_bounds.intersects(dirty) {
             debug!("RenderBox::build_display_list: intersected. Adding display item...");
         } else {
             debug!("RenderBox::build_display_list: Did not intersect...");
             return;
         }
 
-        self.add_bgcolor_to_list(list, &abs_box_bounds); 
+        let list = self.target_display_list(stacking_context);
 
-        match *self {
+        match shadow_bounds {
+            Some(copy bounds) => self.add_box_shadow_to_list(&*list, &bounds),
+            None => ()
+        }
+
+        self.add_bgcolor_to_list(&*list, &abs_box_bounds);
+
+        // Whether a placeholder border was already painted above in lieu
+        // of the image (see the ImageBox arm below); if so, the box's own
+        // CSS border is skipped so the two don't both get painted for the
+        // same box while the bitmap is still loading.
+        let painted_placeholder_border = match *self {
             UnscannedTextBox(*) => fail ~"Shouldn't see unscanned boxes here.",
             TextBox(_,d) => {
                 list.append_item(~dl::Text(copy abs_box_bounds, text_run::serialize(builder.ctx.font_cache, d.run),
-                                           d.offset, d.length))
+                                           d.offset, d.length));
+                false
             },
             // TODO: items for background, border, outline
             GenericBox(_) => {
+                false
             },
             ImageBox(_,i) => {
                 match i.get_image() {
-                    Some(image) => list.append_item(~dl::Image(copy abs_box_bounds, arc::clone(&image))),
-                    /* No image data at all? Okay, add some fallback content instead. */
-                    None => ()
+                    Some(image) => {
+                        list.append_item(~dl::Image(copy abs_box_bounds, arc::clone(&image)));
+                        false
+                    },
+                    /* Bitmap isn't decoded yet. Paint a placeholder at
+                       the already-resolved box size (see
+                       resolve_replaced_dimensions) so the layout doesn't
+                       thrash once the image arrives. */
+                    None => {
+                        let placeholder_color = rgb(204, 204, 204);
+                        list.append_item(~dl::Border(copy abs_box_bounds, au::from_px(1),
+                                                      placeholder_color.red, placeholder_color.green,
+                                                      placeholder_color.blue));
+                        true
+                    }
                 }
             }
-        }
+        };
 
-        self.add_border_to_list(list, abs_box_bounds);
+        if !painted_placeholder_border {
+            self.add_border_to_list(&*list, abs_box_bounds);
+        }
     }
 
     fn add_bgcolor_to_list(list: &dl::DisplayList, abs_bounds: &Rect<au>) {
@@ -472,6 +964,41 @@ impl RenderBox : RenderBoxMethods {
         }
     }
 
+    /* The bounding rect of this box's box-shadow, if it has one: the
+       border box translated by the shadow's offset and inflated by
+       spread + blur * BLUR_INFLATION_FACTOR on each side, so the
+       painting backend's Gaussian blur has room to cover its tail. */
+    pure fn shadow_bounds(abs_bounds: &Rect<au>) -> Option<Rect<au>> {
+        match self.d().node.style().box_shadow {
+            Specified(copy shadow) => {
+                let shadow_offset = Point2D(resolve_length(shadow.offset_x),
+                                             resolve_length(shadow.offset_y));
+                let offset_bounds = abs_bounds.translate(&shadow_offset);
+                let inflation = inflate_for_shadow(resolve_length(shadow.spread_radius),
+                                                    resolve_length(shadow.blur_radius));
+                let inflate_edges = EdgeSizes(inflation, inflation, inflation, inflation);
+                Some(inflate_rect(&offset_bounds, &inflate_edges, &zero_edge_sizes()))
+            }
+            _ => None
+        }
+    }
+
+    /* Appends the box-shadow display item for `shadow_bounds` (the
+       already offset-and-inflated rect computed by `shadow_bounds()`).
+       Painted before the box's own background so outset shadows sit
+       behind the box's opaque content. */
+    fn add_box_shadow_to_list(list: &dl::DisplayList, shadow_bounds: &Rect<au>) {
+        match self.d().node.style().box_shadow {
+            Specified(copy shadow) => {
+                list.append_item(~dl::BoxShadow(copy *shadow_bounds,
+                                                 resolve_length(shadow.blur_radius),
+                                                 resolve_length(shadow.spread_radius),
+                                                 shadow.color));
+            }
+            _ => ()
+        }
+    }
+
     fn add_border_to_list(list: &dl::DisplayList, abs_bounds: Rect<au>) {
         let style = self.d().node.style();
         match style.border_width {